@@ -1,55 +1,238 @@
-#[cfg(target_os = "unix")]
-use mio::unix::EventedFd;
 /// A simple reactor based on mio
 use mio::{
     net::{TcpListener, TcpStream, UdpSocket},
-    Evented, Registration, Token,
+    Interest, Token,
 };
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
 
 use std::collections::*;
+use std::future::Future;
 use std::io::Result as IOResult;
+use std::marker::PhantomPinned;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::*;
 use std::task::*;
 use std::time::*;
 
+/// A per-direction readiness bitset.
+///
+/// mio 0.7 no longer exposes a public `Ready` type, so the reactor carries its
+/// own minimal one to stamp tokens with the directions it has observed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ready(u8);
+
+impl Ready {
+    pub const EMPTY: Ready = Ready(0);
+    pub const READABLE: Ready = Ready(0b01);
+    pub const WRITABLE: Ready = Ready(0b10);
+
+    pub fn is_readable(self) -> bool {
+        self.0 & Ready::READABLE.0 != 0
+    }
+
+    pub fn is_writable(self) -> bool {
+        self.0 & Ready::WRITABLE.0 != 0
+    }
+
+    pub fn contains(self, other: Ready) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Ready {
+    type Output = Ready;
+    fn bitor(self, rhs: Ready) -> Ready {
+        Ready(self.0 | rhs.0)
+    }
+}
+
+impl std::ops::BitAnd for Ready {
+    type Output = Ready;
+    fn bitand(self, rhs: Ready) -> Ready {
+        Ready(self.0 & rhs.0)
+    }
+}
+
+impl std::ops::Not for Ready {
+    type Output = Ready;
+    fn not(self) -> Ready {
+        Ready(!self.0 & 0b11)
+    }
+}
+
 /// don't pretend we can support more envent types than mio provides
 pub enum Event {
     TcpListener(TcpListener),
     TcpStream(TcpStream),
     UdpSocket(UdpSocket),
-    Registration(Registration),
-    #[cfg(target_os = "unix")]
-    EventedFd(EventedFd),
+    #[cfg(unix)]
+    SourceFd(RawFd),
+    /// Write end of an anonymous unix pipe; registered for writability.
+    #[cfg(unix)]
+    PipeSender(mio::unix::pipe::Sender),
+    /// Read end of an anonymous unix pipe; registered for readability.
+    #[cfg(unix)]
+    PipeReceiver(mio::unix::pipe::Receiver),
+}
+
+/// A node in an intrusive readiness wait list.
+///
+/// The node lives inside the awaiting future's stack frame; the reactor only
+/// ever holds raw pointers into it, so storage is owned by the future and
+/// nothing leaks when the future is dropped.
+pub struct Waiter {
+    waker: Option<Waker>,
+    prev: *mut Waiter,
+    next: *mut Waiter,
+    linked: bool,
+    notified: bool,
+}
+
+impl Waiter {
+    pub fn new() -> Waiter {
+        Waiter {
+            waker: None,
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            linked: false,
+            notified: false,
+        }
+    }
+}
+
+/// An intrusive doubly-linked list of [`Waiter`]s for one readiness direction.
+///
+/// Linking and unlinking are O(1) and the list never owns its nodes; waking
+/// walks the list once, visiting every task parked on the source.
+struct WaitList {
+    head: *mut Waiter,
+}
+
+// The raw pointers only ever dangle across a `&mut` held under the registry
+// mutex, so the list is safe to move between threads with the rest of the map.
+unsafe impl Send for WaitList {}
+
+impl WaitList {
+    fn new() -> WaitList {
+        WaitList {
+            head: ptr::null_mut(),
+        }
+    }
+
+    /// Link `node` at the front of the list. The caller must hold the registry
+    /// lock and keep `node` pinned until it is unlinked.
+    unsafe fn push(&mut self, node: *mut Waiter) {
+        if (*node).linked {
+            return;
+        }
+        (*node).prev = ptr::null_mut();
+        (*node).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = node;
+        }
+        self.head = node;
+        (*node).linked = true;
+    }
+
+    unsafe fn unlink(&mut self, node: *mut Waiter) {
+        if !(*node).linked {
+            return;
+        }
+        if (*node).prev.is_null() {
+            self.head = (*node).next;
+        } else {
+            (*(*node).prev).next = (*node).next;
+        }
+        if !(*node).next.is_null() {
+            (*(*node).next).prev = (*node).prev;
+        }
+        (*node).prev = ptr::null_mut();
+        (*node).next = ptr::null_mut();
+        (*node).linked = false;
+    }
+
+    /// Notify and wake every parked waiter, unlinking each as it is drained so
+    /// woken tasks re-arm from an empty list.
+    unsafe fn wake_all(&mut self) {
+        let mut cur = self.head;
+        while !cur.is_null() {
+            let next = (*cur).next;
+            (*cur).notified = true;
+            (*cur).prev = ptr::null_mut();
+            (*cur).next = ptr::null_mut();
+            (*cur).linked = false;
+            if let Some(w) = (*cur).waker.as_ref() {
+                w.wake_by_ref();
+            }
+            cur = next;
+        }
+        self.head = ptr::null_mut();
+    }
 }
 
 pub struct EventInfo {
     pub ev: Event,
-    pub read_waker: Waker,
-    pub write_waker: Waker,
+    /// Interest the socket-like sources are registered with. Pipe ends ignore
+    /// it since their direction is fixed by which end they are.
+    interest: Interest,
+    read_waiters: WaitList,
+    write_waiters: WaitList,
+    /// Readiness the driver has seen but no task has cleared yet.
+    readiness: Ready,
+    /// Driver tick at which `readiness` was last stamped.
+    tick: usize,
 }
 
 impl EventInfo {
-    pub fn register(&self, poll: &mio::Poll, token: Token) -> IOResult<()> {
-        let opts = mio::PollOpt::edge();
-        let interest = mio::Ready::readable() | mio::Ready::writable();;
-        match &self.ev {
-            Event::TcpListener(tl) => tl.register(poll, token, interest, opts),
-            Event::TcpStream(ts) => ts.register(poll, token, interest, opts),
-            Event::UdpSocket(us) => us.register(poll, token, interest, opts),
-            Event::Registration(re) => re.register(poll, token, interest, opts),
-            #[cfg(target_os = "unix")]
-            Event::EventedFd(fd) => fd.register(poll, token, interest, opts),
+    /// Register `ev` for both readability and writability.
+    pub fn new(ev: Event) -> EventInfo {
+        EventInfo::with_interest(ev, Interest::READABLE | Interest::WRITABLE)
+    }
+
+    /// Register `ev` for exactly `interest`; use this for read-only sources such
+    /// as an accept listener that would otherwise take spurious writable wakeups.
+    pub fn with_interest(ev: Event, interest: Interest) -> EventInfo {
+        EventInfo {
+            ev,
+            interest,
+            read_waiters: WaitList::new(),
+            write_waiters: WaitList::new(),
+            readiness: Ready::EMPTY,
+            tick: 0,
         }
     }
 
-    pub fn deregister(&self, poll: &mio::Poll) -> IOResult<()> {
-        match &self.ev {
-            Event::TcpListener(tl) => tl.deregister(poll),
-            Event::TcpStream(ts) => ts.deregister(poll),
-            Event::UdpSocket(us) => us.deregister(poll),
-            Event::Registration(re) => poll.deregister(re),
-            #[cfg(target_os = "unix")]
-            Event::EventedFd(fd) => fd.deregister(poll),
+    pub fn register(&mut self, registry: &mio::Registry, token: Token) -> IOResult<()> {
+        let interest = self.interest;
+        match &mut self.ev {
+            Event::TcpListener(tl) => registry.register(tl, token, interest),
+            Event::TcpStream(ts) => registry.register(ts, token, interest),
+            Event::UdpSocket(us) => registry.register(us, token, interest),
+            #[cfg(unix)]
+            Event::SourceFd(fd) => {
+                registry.register(&mut mio::unix::SourceFd(fd), token, interest)
+            }
+            #[cfg(unix)]
+            Event::PipeSender(tx) => registry.register(tx, token, Interest::WRITABLE),
+            #[cfg(unix)]
+            Event::PipeReceiver(rx) => registry.register(rx, token, Interest::READABLE),
+        }
+    }
+
+    pub fn deregister(&mut self, registry: &mio::Registry) -> IOResult<()> {
+        match &mut self.ev {
+            Event::TcpListener(tl) => registry.deregister(tl),
+            Event::TcpStream(ts) => registry.deregister(ts),
+            Event::UdpSocket(us) => registry.deregister(us),
+            #[cfg(unix)]
+            Event::SourceFd(fd) => registry.deregister(&mut mio::unix::SourceFd(fd)),
+            #[cfg(unix)]
+            Event::PipeSender(tx) => registry.deregister(tx),
+            #[cfg(unix)]
+            Event::PipeReceiver(rx) => registry.deregister(rx),
         }
     }
 }
@@ -67,7 +250,7 @@ impl Registry {
         }
     }
 
-    pub fn register(&mut self, ev_info: EventInfo, poll: &mio::Poll) -> Token {
+    pub fn register(&mut self, mut ev_info: EventInfo, registry: &mio::Registry) -> IOResult<Token> {
         loop {
             let v = self.next_token;
             self.next_token += 1;
@@ -80,9 +263,12 @@ impl Registry {
                     continue;
                 }
                 std::collections::hash_map::RawEntryMut::Vacant(v) => {
-                    ev_info.register(poll, token);
+                    // Only insert once mio has accepted the source, so a failed
+                    // registration never leaves a live token whose source can
+                    // never become ready.
+                    ev_info.register(registry, token)?;
                     v.insert(token, ev_info);
-                    return token;
+                    return Ok(token);
                 }
             }
         }
@@ -93,59 +279,186 @@ impl Registry {
     }
 }
 
+/// Sentinel token for the internal cross-thread wake-up source. Reserving
+/// `usize::MAX` is safe because [`Registry::register`] never hands it out.
+const WAKER_TOKEN: Token = Token(usize::max_value());
+
 /// A simple actor based on mio::Poll
 pub struct Actor {
     registry: Mutex<Registry>,
-    poll: mio::Poll,
+    /// Owns the `Poll` instance the driver blocks on. Held behind a mutex so the
+    /// single-threaded `poll()` call (which needs `&mut Poll`) runs through a
+    /// shared `&Actor`, freeing other threads to register against `mio_registry`
+    /// meanwhile.
+    poll: Mutex<mio::Poll>,
+    /// A cloned `Registry` handle used for (de)registration, so sources can be
+    /// added without touching the `Poll` the driver is blocked on.
+    mio_registry: mio::Registry,
+    /// Monotonically increasing counter bumped once per [`Actor::wait_all_events`]
+    /// call, used to fence concurrent readiness clears against re-arming events.
+    /// Atomic so the driver can bump it through `&self`, letting other threads
+    /// register/deregister (and wake the loop) while it is blocked in `poll()`.
+    tick: AtomicUsize,
+    waker: Arc<mio::Waker>,
+    shutdown: Arc<AtomicBool>,
+}
+
+/// A readiness observation handed to a task along with the driver tick that
+/// produced it, so the task can clear the stored bits without racing a later
+/// poll that re-readied the same source.
+#[derive(Clone, Copy)]
+pub struct ReadyEvent {
+    pub tick: usize,
+    pub readiness: Ready,
+}
+
+/// A cheap, cloneable channel to the event loop that any thread may use to
+/// interrupt a blocked `poll()` so it picks up registry changes or stops.
+#[derive(Clone)]
+pub struct Handle {
+    waker: Arc<mio::Waker>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Handle {
+    /// Unblock the event loop so it re-reads the registry.
+    pub fn wake(&self) -> IOResult<()> {
+        self.waker.wake()
+    }
+
+    /// Ask the event loop to stop, then wake it so it observes the request.
+    pub fn shutdown(&self) -> IOResult<()> {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.wake()
+    }
 }
 
 impl Actor {
     pub fn new() -> IOResult<Actor> {
         let poll = mio::Poll::new()?;
+        let waker = Arc::new(mio::Waker::new(poll.registry(), WAKER_TOKEN)?);
+        let mio_registry = poll.registry().try_clone()?;
         Ok(Actor {
             registry: Mutex::new(Registry::new()),
-            poll,
+            poll: Mutex::new(poll),
+            mio_registry,
+            tick: AtomicUsize::new(0),
+            waker,
+            shutdown: Arc::new(AtomicBool::new(false)),
         })
     }
 
-    pub fn register(&mut self, ev_info: EventInfo) -> IOResult<Token> {
+    /// A cloneable [`Handle`] any thread can use to interrupt the event loop.
+    pub fn handle(&self) -> Handle {
+        Handle {
+            waker: self.waker.clone(),
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    pub fn register(&self, ev_info: EventInfo) -> IOResult<Token> {
         let token;
         {
             let registry = &mut self.registry.lock().unwrap();
-            token = registry.register(ev_info, &self.poll);
+            token = registry.register(ev_info, &self.mio_registry)?;
         }
+        // Nudge the loop so a blocked poll starts servicing the new source.
+        self.waker.wake()?;
         Ok(token)
     }
 
-    pub fn deregister(&mut self, token: Token) -> IOResult<bool> {
-        let registry = &mut self.registry.lock().unwrap();
-        if let Some(v) = registry.remove(token) {
-            v.deregister(&self.poll)?;
-            return Ok(true);
-        } else {
-            return Ok(false);
+    pub fn deregister(&self, token: Token) -> IOResult<bool> {
+        let found;
+        {
+            let registry = &mut self.registry.lock().unwrap();
+            if let Some(mut v) = registry.remove(token) {
+                v.deregister(&self.mio_registry)?;
+                found = true;
+            } else {
+                found = false;
+            }
+        }
+        self.waker.wake()?;
+        Ok(found)
+    }
+
+    /// Wait until `token`'s source is ready in `interest`'s direction.
+    ///
+    /// The returned future parks an intrusive [`Waiter`] on the matching
+    /// direction's list while pending and unlinks it on drop, so any number of
+    /// tasks may await the same source without clobbering one another.
+    pub fn readiness(&self, token: Token, interest: Ready) -> Readiness {
+        Readiness {
+            actor: self,
+            token,
+            readable: interest.is_readable(),
+            waiter: Waiter::new(),
+            linked: false,
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Clear the bits a task observed in `event`, but only if the source has
+    /// not been re-readied since: if the stored tick has advanced past
+    /// `event.tick` the readiness is left set so the source is re-polled.
+    pub fn clear_readiness(&self, token: Token, event: ReadyEvent) {
+        let mut registry = self.registry.lock().unwrap();
+        if let Some(info) = registry.map.get_mut(&token) {
+            if info.tick == event.tick {
+                info.readiness = info.readiness & !event.readiness;
+            }
         }
     }
 
-    pub fn wait_all_events(&mut self, timeout: Option<Duration>) -> IOResult<usize> {
+    /// Whether a [`Handle::shutdown`] has been requested; a driver loop should
+    /// break once this returns `true`.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    pub fn wait_all_events(&self, timeout: Option<Duration>) -> IOResult<usize> {
         let mut events = mio::Events::with_capacity(1024);
-        self.poll.poll(&mut events, timeout)?;
+        {
+            let mut poll = self.poll.lock().unwrap();
+            poll.poll(&mut events, timeout)?;
+        }
+        let tick = self.tick.fetch_add(1, Ordering::SeqCst) + 1;
         let mut ret: usize = 0;
         {
             let mut registry = self.registry.lock().unwrap();
             for ev in events.iter() {
                 let token = ev.token();
-                match registry.map.get(&token) {
+                if token == WAKER_TOKEN {
+                    // The sentinel only unblocks the loop; the caller re-reads
+                    // the registry (or stops on shutdown). Nothing to dispatch.
+                    continue;
+                }
+                match registry.map.get_mut(&token) {
                     None => {
                         continue;
                     }
-                    Some(ref mut v) => {
-                        let readiness = ev.readiness();
-                        if readiness.contains(mio::Ready::readable()) {
-                            v.read_waker.wake();
+                    Some(v) => {
+                        let mut readiness = Ready::EMPTY;
+                        // A pipe hangup (writer closed) shows up as a read-close
+                        // rather than plain readability; surface it as readable
+                        // so a parked reader wakes and observes the EOF.
+                        if ev.is_readable() || ev.is_read_closed() {
+                            readiness = readiness | Ready::READABLE;
+                        }
+                        if ev.is_writable() {
+                            readiness = readiness | Ready::WRITABLE;
                         }
-                        if readiness.contains(mio::Ready::writable()) {
-                            v.write_waker.wake();
+                        // Record the readiness and stamp it before waking so a
+                        // woken task sees the bits and the tick together.
+                        v.readiness = v.readiness | readiness;
+                        v.tick = tick;
+                        unsafe {
+                            if readiness.is_readable() {
+                                v.read_waiters.wake_all();
+                            }
+                            if readiness.is_writable() {
+                                v.write_waiters.wake_all();
+                            }
                         }
                     }
                 }
@@ -155,3 +468,189 @@ impl Actor {
         Ok(ret)
     }
 }
+
+/// A future that resolves once its source becomes ready in one direction.
+///
+/// It owns the [`Waiter`] node it parks on the reactor's wait list, so the
+/// node's storage is reclaimed the moment the future is dropped.
+pub struct Readiness<'a> {
+    actor: &'a Actor,
+    token: Token,
+    readable: bool,
+    waiter: Waiter,
+    linked: bool,
+    _pin: PhantomPinned,
+}
+
+impl Future for Readiness<'_> {
+    type Output = IOResult<ReadyEvent>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IOResult<ReadyEvent>> {
+        // SAFETY: we never move out of `waiter`, only hand the reactor a raw
+        // pointer to it that is invalidated on drop.
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut registry = this.actor.registry.lock().unwrap();
+        let info = match registry.map.get_mut(&this.token) {
+            // Source is no longer registered. Surface a terminal error rather
+            // than a fake ready, which would make callers re-issue the syscall,
+            // get `WouldBlock`, re-arm, and spin at 100% CPU.
+            None => {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::NotConnected,
+                    "readiness source is no longer registered",
+                )))
+            }
+            Some(info) => info,
+        };
+        // Report only the direction this future awaited; handing back the full
+        // bitset would let a woken reader clear a concurrent writer's WRITABLE
+        // bit (or vice versa) before the writer observes it.
+        let dir = if this.readable {
+            Ready::READABLE
+        } else {
+            Ready::WRITABLE
+        };
+        if this.waiter.notified {
+            this.linked = false;
+            return Poll::Ready(Ok(ReadyEvent {
+                tick: info.tick,
+                readiness: info.readiness & dir,
+            }));
+        }
+        // Consult readiness stored by the driver before parking. Without this a
+        // wakeup delivered between the caller's `WouldBlock` syscall and this
+        // park is lost: the driver OR'd the bit in and woke an empty wait list,
+        // and the edge-triggered source will not re-fire for the fresh waiter.
+        if info.readiness.contains(dir) {
+            return Poll::Ready(Ok(ReadyEvent {
+                tick: info.tick,
+                readiness: info.readiness & dir,
+            }));
+        }
+        let node: *mut Waiter = &mut this.waiter;
+        unsafe {
+            (*node).waker = Some(cx.waker().clone());
+            if this.readable {
+                info.read_waiters.push(node);
+            } else {
+                info.write_waiters.push(node);
+            }
+        }
+        this.linked = true;
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::{waker, ArcWake};
+    use std::sync::atomic::AtomicUsize;
+
+    /// A waker that counts how many times it was woken.
+    struct Counter(AtomicUsize);
+
+    impl ArcWake for Counter {
+        fn wake_by_ref(arc: &Arc<Self>) {
+            arc.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn wait_list_wakes_every_parked_waiter() {
+        let counter = Arc::new(Counter(AtomicUsize::new(0)));
+        let w = waker(counter.clone());
+        let mut list = WaitList::new();
+        // More than one waiter: the old single-slot design could only ever
+        // wake the last registrant, so anything above 1 exercises the fix.
+        let mut nodes: Vec<Box<Waiter>> = (0..4)
+            .map(|_| {
+                let mut node = Box::new(Waiter::new());
+                node.waker = Some(w.clone());
+                node
+            })
+            .collect();
+        unsafe {
+            for node in nodes.iter_mut() {
+                list.push(&mut **node as *mut Waiter);
+            }
+            list.wake_all();
+        }
+        assert_eq!(counter.0.load(Ordering::SeqCst), 4);
+        assert!(list.head.is_null());
+        for node in &nodes {
+            assert!(node.notified);
+            assert!(!node.linked);
+        }
+    }
+
+    /// A stale `clear_readiness` (one whose tick predates a concurrent re-ready)
+    /// must leave the readiness set so the re-readied source is re-polled, while
+    /// a current-tick clear still clears.
+    #[test]
+    fn clear_readiness_is_tick_fenced() {
+        let actor = Actor::new().unwrap();
+        let listener =
+            TcpListener::bind("127.0.0.1:0".parse().unwrap()).expect("bind loopback listener");
+        let token = actor.register(EventInfo::new(Event::TcpListener(listener))).unwrap();
+
+        // Driver stamps READABLE at tick 1.
+        {
+            let mut registry = actor.registry.lock().unwrap();
+            let info = registry.map.get_mut(&token).unwrap();
+            info.readiness = Ready::READABLE;
+            info.tick = 1;
+        }
+
+        // A clear carrying the old tick 0 races a source that re-readied at
+        // tick 1; it must NOT drop the fresh bit.
+        actor.clear_readiness(
+            token,
+            ReadyEvent {
+                tick: 0,
+                readiness: Ready::READABLE,
+            },
+        );
+        {
+            let mut registry = actor.registry.lock().unwrap();
+            assert!(registry.map.get_mut(&token).unwrap().readiness.is_readable());
+        }
+
+        // A clear at the current tick clears as expected.
+        actor.clear_readiness(
+            token,
+            ReadyEvent {
+                tick: 1,
+                readiness: Ready::READABLE,
+            },
+        );
+        {
+            let mut registry = actor.registry.lock().unwrap();
+            assert_eq!(
+                registry.map.get_mut(&token).unwrap().readiness,
+                Ready::EMPTY
+            );
+        }
+
+        let _ = actor.deregister(token);
+    }
+}
+
+impl Drop for Readiness<'_> {
+    fn drop(&mut self) {
+        if !self.linked {
+            return;
+        }
+        let mut registry = self.actor.registry.lock().unwrap();
+        if let Some(info) = registry.map.get_mut(&self.token) {
+            let node: *mut Waiter = &mut self.waiter;
+            unsafe {
+                if self.readable {
+                    info.read_waiters.unlink(node);
+                } else {
+                    info.write_waiters.unlink(node);
+                }
+            }
+        }
+    }
+}
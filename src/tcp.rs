@@ -1,16 +1,211 @@
-use mio::tcp::{TcpListener as MioTcpListener, TcpStream as MioTcpStream};
-use futures::{Poll, stream::Stream, task::LocalWaker};
-use std::net::addr::SocketAddr;
+//! TCP accept stream and connection types backed by the [`Actor`] reactor.
+//!
+//! These wrap their sockets and register them by raw fd, so the module is
+//! Unix-only; it compiles away entirely on other platforms.
+#![cfg(unix)]
+
+use crate::actor::{Actor, Event, EventInfo, Ready, Readiness};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::Stream;
+use mio::net::{TcpListener as MioTcpListener, TcpStream as MioTcpStream};
+use mio::{Interest, Token};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
 use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// An accept stream backed by the [`Actor`] reactor.
+///
+/// The listener's file descriptor is registered for readability; each readable
+/// event means one or more connections are pending, so `poll_next` drains the
+/// backlog with repeated `accept()` calls before re-arming on `WouldBlock`.
+pub struct TcpListener<'a> {
+    actor: &'a Actor,
+    listener: MioTcpListener,
+    token: Token,
+    ready: Option<Pin<Box<Readiness<'a>>>>,
+}
+
+impl<'a> TcpListener<'a> {
+    /// Register `listener` with `actor` and return an accept stream over it.
+    pub fn new(actor: &'a Actor, listener: MioTcpListener) -> io::Result<TcpListener<'a>> {
+        // A listener only ever becomes readable, so register read-only to avoid
+        // spurious writable wakeups.
+        let token = actor.register(EventInfo::with_interest(
+            Event::SourceFd(listener.as_raw_fd()),
+            Interest::READABLE,
+        ))?;
+        Ok(TcpListener {
+            actor,
+            listener,
+            token,
+            ready: None,
+        })
+    }
+}
+
+impl<'a> Stream for TcpListener<'a> {
+    type Item = (TcpStream<'a>, SocketAddr);
 
-struct TcpListener;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.listener.accept() {
+                Ok((stream, addr)) => match TcpStream::new(this.actor, stream) {
+                    Ok(stream) => return Poll::Ready(Some((stream, addr))),
+                    // Registering the accepted socket with the reactor failed
+                    // (e.g. epoll out of memory or watch slots). Drop this
+                    // connection and keep draining the backlog rather than
+                    // ending the accept stream for good.
+                    Err(_) => continue,
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if this.ready.is_none() {
+                        this.ready = Some(Box::pin(this.actor.readiness(this.token, Ready::READABLE)));
+                    }
+                    match this.ready.as_mut().unwrap().as_mut().poll(cx) {
+                        Poll::Ready(Ok(ev)) => {
+                            this.ready = None;
+                            this.actor.clear_readiness(this.token, ev);
+                            continue;
+                        }
+                        // The listener was deregistered out from under us; the
+                        // stream is genuinely finished.
+                        Poll::Ready(Err(_)) => {
+                            this.ready = None;
+                            return Poll::Ready(None);
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                // EINTR and a connection aborted before we accepted it are
+                // transient; a POSIX accept loop retries them. Only a genuinely
+                // fatal error ends the stream.
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::Interrupted
+                        || e.kind() == io::ErrorKind::ConnectionAborted =>
+                {
+                    continue
+                }
+                Err(_) => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Drop for TcpListener<'_> {
+    fn drop(&mut self) {
+        let _ = self.actor.deregister(self.token);
+    }
+}
+
+/// A TCP connection registered with the [`Actor`] reactor.
+///
+/// Reads and writes drive the per-token readiness tracking: on `WouldBlock` the
+/// relevant direction is awaited through the reactor and retried once ready.
+pub struct TcpStream<'a> {
+    actor: &'a Actor,
+    stream: MioTcpStream,
+    token: Token,
+    read_ready: Option<Pin<Box<Readiness<'a>>>>,
+    write_ready: Option<Pin<Box<Readiness<'a>>>>,
+}
 
-impl Stream for TcpListener {
-    type item = (TcpStream, SocketAddr);
-    fn poll_next(
+impl<'a> TcpStream<'a> {
+    /// Register `stream` with `actor` for readability and writability.
+    pub fn new(actor: &'a Actor, stream: MioTcpStream) -> io::Result<TcpStream<'a>> {
+        let token = actor.register(EventInfo::new(Event::SourceFd(stream.as_raw_fd())))?;
+        Ok(TcpStream {
+            actor,
+            stream,
+            token,
+            read_ready: None,
+            write_ready: None,
+        })
+    }
+}
+
+impl<'a> AsyncRead for TcpStream<'a> {
+    fn poll_read(
         self: Pin<&mut Self>,
-        lw: &LocalWaker) -> Poll<Option<Self::Item>> {
-        panic!();
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match this.stream.read(buf) {
+                Ok(n) => return Poll::Ready(Ok(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if this.read_ready.is_none() {
+                        this.read_ready =
+                            Some(Box::pin(this.actor.readiness(this.token, Ready::READABLE)));
+                    }
+                    match this.read_ready.as_mut().unwrap().as_mut().poll(cx) {
+                        Poll::Ready(Ok(ev)) => {
+                            this.read_ready = None;
+                            this.actor.clear_readiness(this.token, ev);
+                            continue;
+                        }
+                        Poll::Ready(Err(e)) => {
+                            this.read_ready = None;
+                            return Poll::Ready(Err(e));
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
     }
 }
 
+impl<'a> AsyncWrite for TcpStream<'a> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match this.stream.write(buf) {
+                Ok(n) => return Poll::Ready(Ok(n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if this.write_ready.is_none() {
+                        this.write_ready =
+                            Some(Box::pin(this.actor.readiness(this.token, Ready::WRITABLE)));
+                    }
+                    match this.write_ready.as_mut().unwrap().as_mut().poll(cx) {
+                        Poll::Ready(Ok(ev)) => {
+                            this.write_ready = None;
+                            this.actor.clear_readiness(this.token, ev);
+                            continue;
+                        }
+                        Poll::Ready(Err(e)) => {
+                            this.write_ready = None;
+                            return Poll::Ready(Err(e));
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().stream.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+        use std::net::Shutdown;
+        Poll::Ready(self.get_mut().stream.shutdown(Shutdown::Write))
+    }
+}
+
+impl Drop for TcpStream<'_> {
+    fn drop(&mut self) {
+        let _ = self.actor.deregister(self.token);
+    }
+}